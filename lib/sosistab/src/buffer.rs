@@ -0,0 +1,140 @@
+use bytes::{Bytes, BytesMut};
+use std::collections::VecDeque;
+
+/// A FIFO byte buffer built from a queue of [`Bytes`] chunks.
+///
+/// Bytes are pushed on the right with [`BytesBuf::extend`] and popped from the
+/// left with [`BytesBuf::take_at_most`] / [`BytesBuf::take_exact`]. Because each
+/// chunk is a reference-counted [`Bytes`], popping a span that stays within a
+/// single chunk hands back a cheap slice and copies nothing; only a request that
+/// straddles an internal chunk boundary allocates and copies. This makes it a
+/// good fit for the reliable read path and FEC reassembly, where the current
+/// `Bytes::copy_from_slice` / `Vec<Vec<u8>>` pattern churns allocations on the
+/// hot forwarding path.
+#[derive(Debug, Default)]
+pub struct BytesBuf {
+    chunks: VecDeque<Bytes>,
+    buf_len: usize,
+}
+
+impl BytesBuf {
+    /// Creates an empty buffer.
+    pub fn new() -> Self {
+        BytesBuf::default()
+    }
+
+    /// Appends a chunk to the right end of the buffer.
+    pub fn extend(&mut self, bts: Bytes) {
+        if !bts.is_empty() {
+            self.buf_len += bts.len();
+            self.chunks.push_back(bts);
+        }
+    }
+
+    /// Total number of buffered bytes.
+    pub fn len(&self) -> usize {
+        self.buf_len
+    }
+
+    /// Returns true when no bytes are buffered.
+    pub fn is_empty(&self) -> bool {
+        self.buf_len == 0
+    }
+
+    /// Pops up to `n` bytes from the left, never crossing a chunk boundary.
+    ///
+    /// The returned [`Bytes`] is always a cheap slice of an existing chunk, so
+    /// this never allocates. It may return fewer than `n` bytes when the front
+    /// chunk is shorter than `n`; call it in a loop to drain larger spans.
+    pub fn take_at_most(&mut self, n: usize) -> Bytes {
+        if n == 0 {
+            return Bytes::new();
+        }
+        let front = match self.chunks.front_mut() {
+            Some(front) => front,
+            None => return Bytes::new(),
+        };
+        if n >= front.len() {
+            let out = self.chunks.pop_front().unwrap();
+            self.buf_len -= out.len();
+            out
+        } else {
+            let out = front.split_to(n);
+            self.buf_len -= n;
+            out
+        }
+    }
+
+    /// Pops exactly `n` bytes from the left, or `None` if fewer are buffered.
+    ///
+    /// Allocates only when the span crosses a chunk boundary; a span contained
+    /// in the front chunk is returned as a cheap slice.
+    pub fn take_exact(&mut self, n: usize) -> Option<Bytes> {
+        if n > self.buf_len {
+            return None;
+        }
+        if n == 0 {
+            return Some(Bytes::new());
+        }
+        // Cheap path: the whole span lives inside the front chunk.
+        if self.chunks.front().map(|f| f.len() >= n).unwrap_or(false) {
+            return Some(self.take_at_most(n));
+        }
+        let mut out = BytesMut::with_capacity(n);
+        while out.len() < n {
+            let piece = self.take_at_most(n - out.len());
+            out.extend_from_slice(&piece);
+        }
+        Some(out.freeze())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn take_at_most_drains_within_a_chunk() {
+        let mut buf = BytesBuf::new();
+        buf.extend(Bytes::from_static(b"hello"));
+        assert_eq!(buf.len(), 5);
+        assert_eq!(&buf.take_at_most(3)[..], b"hel");
+        assert_eq!(buf.len(), 2);
+        assert_eq!(&buf.take_at_most(10)[..], b"lo");
+        assert!(buf.is_empty());
+        assert_eq!(&buf.take_at_most(4)[..], b"");
+    }
+
+    #[test]
+    fn take_at_most_never_crosses_a_boundary() {
+        let mut buf = BytesBuf::new();
+        buf.extend(Bytes::from_static(b"ab"));
+        buf.extend(Bytes::from_static(b"cd"));
+        // Asking for 4 only returns the 2 bytes in the front chunk.
+        assert_eq!(&buf.take_at_most(4)[..], b"ab");
+        assert_eq!(&buf.take_at_most(4)[..], b"cd");
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn extend_ignores_empty_chunks() {
+        let mut buf = BytesBuf::new();
+        buf.extend(Bytes::new());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn take_exact_honours_the_boundary_and_length() {
+        let mut buf = BytesBuf::new();
+        buf.extend(Bytes::from_static(b"ab"));
+        buf.extend(Bytes::from_static(b"cd"));
+        // Not enough buffered.
+        assert!(buf.take_exact(10).is_none());
+        // Crosses the chunk boundary, so it allocates and copies.
+        assert_eq!(&buf.take_exact(3).unwrap()[..], b"abc");
+        assert_eq!(buf.len(), 1);
+        // Remainder is a cheap slice of the trailing chunk.
+        assert_eq!(&buf.take_exact(1).unwrap()[..], b"d");
+        assert!(buf.is_empty());
+    }
+}
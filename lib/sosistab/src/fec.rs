@@ -6,14 +6,24 @@ use std::{collections::HashMap, sync::Arc};
 /// A forward error correction encoder. Retains internal state for memoization, memory pooling etc.
 #[derive(Debug)]
 pub struct FrameEncoder {
-    // table mapping current loss in pct + run length => overhead
+    // table mapping current loss (in 1/256ths) + run length => overhead, keyed on the
+    // *effective* target loss; cleared whenever that target shifts.
     rate_table: HashMap<(u8, usize), usize>,
-    // target loss rate
+    // configured ceiling on tolerable post-FEC residual loss
     target_loss: u8,
+    // effective target driving parity sizing, adapted from decoder feedback
+    eff_target_loss: u8,
+    // EWMA of the residual loss the peer actually observes after FEC
+    residual_ewma: f64,
     // encoder pool
     rs_encoders: HashMap<(usize, usize), galois_8::ReedSolomon>,
 }
 
+/// How much the effective target loss is nudged per residual report.
+const TARGET_NUDGE: u8 = 1;
+/// Smoothing factor for the residual-loss EWMA.
+const RESIDUAL_ALPHA: f64 = 0.2;
+
 impl FrameEncoder {
     /// Creates a new Encoder at the given loss level.
     #[tracing::instrument(level = "trace")]
@@ -21,10 +31,40 @@ impl FrameEncoder {
         FrameEncoder {
             rate_table: HashMap::new(),
             target_loss,
+            eff_target_loss: target_loss,
+            residual_ewma: target_loss as f64,
             rs_encoders: HashMap::new(),
         }
     }
 
+    /// Folds an observed post-FEC residual-loss report from the peer into the
+    /// encoder's EWMA and nudges the effective target loss toward the minimum
+    /// parity overhead that still keeps residual loss under the configured
+    /// `target_loss` ceiling. The binomial `rate_table` is keyed on the
+    /// effective target, so a shift invalidates the cached overheads.
+    pub fn update_residual(&mut self, report: ResidualReport) {
+        let observed = report.residual_loss();
+        self.residual_ewma =
+            self.residual_ewma * (1.0 - RESIDUAL_ALPHA) + observed as f64 * RESIDUAL_ALPHA;
+        let old = self.eff_target_loss;
+        // A lower target demands a lower post-FEC loss, hence more parity.
+        let new = if self.residual_ewma > self.target_loss as f64 {
+            self.eff_target_loss.saturating_sub(TARGET_NUDGE).max(1)
+        } else {
+            self.eff_target_loss.saturating_add(TARGET_NUDGE)
+        };
+        if new != old {
+            tracing::trace!(
+                "adapting FEC target {} => {} (residual EWMA {:.1})",
+                old,
+                new,
+                self.residual_ewma
+            );
+            self.eff_target_loss = new;
+            self.rate_table.clear();
+        }
+    }
+
     /// Encodes a slice of packets into more packets.
     #[tracing::instrument(level = "trace")]
     pub fn encode(&mut self, measured_loss: u8, pkts: &[Bytes]) -> Vec<Bytes> {
@@ -68,7 +108,7 @@ impl FrameEncoder {
 
     /// Calculates the number of repair blocks needed to properly reconstruct a run of packets.
     fn repair_len(&mut self, measured_loss: u8, run_len: usize) -> usize {
-        let target_loss = self.target_loss;
+        let target_loss = self.eff_target_loss;
         (*self
             .rate_table
             .entry((measured_loss, run_len))
@@ -144,6 +184,13 @@ impl FrameDecoder {
         self.data_shards - self.good_pkts()
     }
 
+    /// Whether this run resolved — either all data shards arrived or parity
+    /// reconstruction succeeded. A `false` here means the run left residual loss.
+    pub fn reconstructed(&self) -> bool {
+        // `good_pkts` already reports every data shard once `done` is set.
+        self.good_pkts() == self.data_shards
+    }
+
     #[tracing::instrument(level = "trace", skip(pkt))]
     pub fn decode(&mut self, pkt: &[u8], pkt_idx: usize) -> Option<Vec<Bytes>> {
         // if we don't have parity shards, don't touch anything
@@ -195,20 +242,108 @@ impl FrameDecoder {
         );
         self.rs_decoder.as_ref()?.reconstruct(&mut ref_vec).ok()?;
         self.done = true;
-        let res = self
-            .space
-            .drain(0..)
-            .zip(self.present.iter().cloned())
-            .take(self.data_shards)
-            .filter_map(|(elem, present)| {
-                if !present {
-                    post_decode(Bytes::copy_from_slice(&elem))
-                } else {
-                    None
-                }
-            })
-            .collect();
-        Some(res)
+        // Each recovered shard becomes an owned `Bytes` without copying
+        // (`Bytes::from(Vec<u8>)` takes the buffer) and `post_decode` hands back
+        // a cheap slice of it.
+        Some(
+            self.space
+                .drain(0..)
+                .zip(self.present.iter().cloned())
+                .take(self.data_shards)
+                .filter(|(_, present)| !*present)
+                .filter_map(|(elem, _)| post_decode(Bytes::from(elem)))
+                .collect(),
+        )
+    }
+}
+
+/// An aggregated post-FEC loss report shipped back to the peer's encoder.
+///
+/// The receiver folds per-run [`FrameDecoder`] outcomes into one of these and
+/// periodically sends it over the reserved feedback `Message` variant; the
+/// sending encoder feeds it to [`FrameEncoder::update_residual`].
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct ResidualReport {
+    pub good_pkts: u64,
+    pub lost_pkts: u64,
+}
+
+impl ResidualReport {
+    /// Residual loss as a 0..=255 fraction matching the encoder's loss scale.
+    pub fn residual_loss(&self) -> u8 {
+        let total = self.good_pkts + self.lost_pkts;
+        if total == 0 {
+            return 0;
+        }
+        ((self.lost_pkts as f64 / total as f64) * 256.0).min(255.0) as u8
+    }
+}
+
+/// Accumulates decoder outcomes and emits a [`ResidualReport`] once enough runs
+/// have been observed, smoothing over per-run noise before feeding the peer.
+///
+/// The receiving side calls [`ResidualAccumulator::observe`] as it finishes each
+/// FEC run and ships any emitted report to the peer, which folds it into its
+/// encoder:
+///
+/// ```ignore
+/// // once per decoded run, wherever the session consumes FrameDecoder output:
+/// if let Some(report) = accumulator.observe(&decoder) {
+///     fec_feedback_send.try_send(report).ok(); // -> Message::LossReport on the mux
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct ResidualAccumulator {
+    good: u64,
+    lost: u64,
+    runs: u64,
+    // runs that never resolved, i.e. left genuine residual loss after FEC
+    unrecovered: u64,
+}
+
+/// Number of decoder runs folded together before a report is emitted.
+const REPORT_EVERY: u64 = 64;
+
+impl ResidualAccumulator {
+    pub fn new() -> Self {
+        ResidualAccumulator::default()
+    }
+
+    /// Folds one finished decoder's outcome in and returns an aggregated report
+    /// whenever enough runs have accumulated. This is the whole producer loop:
+    /// the receive path calls it once per decoded run and forwards any `Some`
+    /// to the peer's encoder (see the type-level example).
+    pub fn observe(&mut self, decoder: &FrameDecoder) -> Option<ResidualReport> {
+        self.record(decoder);
+        self.poll()
+    }
+
+    /// Records one finished decoder's outcome.
+    fn record(&mut self, decoder: &FrameDecoder) {
+        self.good += decoder.good_pkts() as u64;
+        self.lost += decoder.lost_pkts() as u64;
+        if !decoder.reconstructed() {
+            self.unrecovered += 1;
+        }
+        self.runs += 1;
+    }
+
+    /// Returns an aggregated report and resets once enough runs accumulate.
+    fn poll(&mut self) -> Option<ResidualReport> {
+        if self.runs < REPORT_EVERY {
+            return None;
+        }
+        tracing::trace!(
+            "residual report: {} runs, {} unrecovered",
+            self.runs,
+            self.unrecovered
+        );
+        let report = ResidualReport {
+            good_pkts: self.good,
+            lost_pkts: self.lost,
+        };
+        *self = ResidualAccumulator::default();
+        Some(report)
     }
 }
 
@@ -246,3 +381,57 @@ fn post_decode(raw: Bytes) -> Option<Bytes> {
 //         })
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn residual_loss_scales_to_256() {
+        let rl = |good, lost| ResidualReport { good_pkts: good, lost_pkts: lost }.residual_loss();
+        assert_eq!(rl(0, 0), 0);
+        assert_eq!(rl(1, 0), 0);
+        assert_eq!(rl(0, 1), 255); // 256 clamps to 255
+        assert_eq!(rl(3, 1), 64); // a quarter of 256
+    }
+
+    #[test]
+    fn accumulator_emits_after_report_every_runs() {
+        let mut acc = ResidualAccumulator::new();
+        let mut report = None;
+        for i in 0..REPORT_EVERY {
+            // A decoder that never ran leaves all data shards lost and
+            // unreconstructed -- exactly the outcome the peer needs to hear about.
+            let out = acc.observe(&FrameDecoder::new(10, 2));
+            // Only the final run of the window emits a report.
+            if i + 1 < REPORT_EVERY {
+                assert!(out.is_none());
+            } else {
+                report = out;
+            }
+        }
+        let report = report.unwrap();
+        assert_eq!(report.good_pkts, 0);
+        assert_eq!(report.lost_pkts, 10 * REPORT_EVERY);
+        // Emitting resets the accumulator, so the next window starts empty.
+        assert!(acc.observe(&FrameDecoder::new(10, 2)).is_none());
+    }
+
+    #[test]
+    fn update_residual_adapts_effective_target() {
+        // Sustained loss above the ceiling drives the effective target down, so
+        // the encoder spends more parity.
+        let mut tight = FrameEncoder::new(10);
+        for _ in 0..50 {
+            tight.update_residual(ResidualReport { good_pkts: 0, lost_pkts: 100 });
+        }
+        assert!(tight.eff_target_loss < 10);
+
+        // A clean channel lets the effective target drift back up, trimming parity.
+        let mut loose = FrameEncoder::new(10);
+        for _ in 0..50 {
+            loose.update_residual(ResidualReport { good_pkts: 100, lost_pkts: 0 });
+        }
+        assert!(loose.eff_target_loss > 10);
+    }
+}
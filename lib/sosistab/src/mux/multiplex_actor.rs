@@ -1,22 +1,53 @@
+use crate::fec::ResidualReport;
 use crate::*;
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use dashmap::DashMap;
 use mux::relconn::{RelConn, RelConnBack, RelConnState};
 use mux::structs::*;
 use rand::prelude::*;
 use smol::channel::{Receiver, Sender};
 use smol::prelude::*;
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Priority given to streams that do not request a specific class.
+const DEFAULT_PRIORITY: u8 = 128;
+/// Number of deficit-round-robin priority classes, highest index served first.
+const PRIORITY_CLASSES: usize = 4;
+/// Bytes of credit handed to each non-empty class per scheduling round.
+const PRIORITY_QUANTUM: usize = 16 * 1024;
+
+/// Length of the fragment header prefixed to every unreliable datagram:
+/// `(u32 msg_id, u16 frag_idx, u16 frag_count)`, little-endian.
+const FRAG_HEADER_LEN: usize = 8;
+/// Payload carried per fragment, sized to sit comfortably under a path MTU.
+const FRAG_MTU: usize = 1024;
+/// How long a partial datagram waits for its missing fragments before eviction.
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Upper bound on concurrently-reassembling datagrams; oldest dropped on overflow.
+const MAX_REASSEMBLIES: usize = 256;
 
 pub async fn multiplex(
     session: Arc<Session>,
     urel_recv_send: Sender<Bytes>,
-    conn_open_recv: Receiver<(Option<String>, Sender<RelConn>)>,
+    urel_send_recv: Receiver<Bytes>,
+    fec_feedback_recv: Receiver<ResidualReport>,
+    conn_open_recv: Receiver<(Option<String>, u8, Sender<RelConn>)>,
     conn_accept_send: Sender<RelConn>,
 ) -> anyhow::Result<()> {
     let conn_tab = Arc::new(ConnTable::default());
-    let (glob_send, glob_recv) = smol::channel::bounded(1000);
+    // Outgoing frames are served by a deficit-round-robin scheduler (see
+    // `PriorityScheduler`) keyed on the originating stream's priority, so
+    // latency-sensitive streams are not head-of-line-blocked by bulk ones.
+    // Priority is a purely local scheduling hint, so it is carried neither by
+    // this channel nor on the wire; it is looked up per frame from `conn_tab`.
+    let (glob_send, glob_recv) = smol::channel::bounded::<Message>(1000);
     let (dead_send, dead_recv) = smol::channel::unbounded();
+    let mut sched = PriorityScheduler::new();
+    let mut reasm = Reassembler::new();
+    // Monotonic id tagging each outgoing datagram's fragments together.
+    let mut urel_msg_id: u32 = 0;
     loop {
         // fires on receiving messages
         let recv_evt = async {
@@ -28,10 +59,23 @@ pub async fn multiplex(
             if let Ok(msg) = msg {
                 match msg {
                     // unreliable
-                    Message::Urel(bts) => {
-                        tracing::trace!("urel recv {}B", bts.len());
-                        if urel_recv_send.try_send(bts).is_err() {
-                            tracing::warn!("urel recv overflow");
+                    Message::Urel {
+                        body,
+                        #[cfg(feature = "trace-ctx")]
+                        trace_ctx,
+                    } => {
+                        // Link datagram handling to the remote span that sent it.
+                        let span = tracing::trace_span!("urel_recv");
+                        #[cfg(feature = "trace-ctx")]
+                        link_remote_parent(&span, &trace_ctx);
+                        let _span = span.entered();
+                        tracing::trace!("urel recv {}B", body.len());
+                        // A datagram may have been split into MTU-sized fragments
+                        // on the way in; only forward once fully reassembled.
+                        if let Some(whole) = reasm.accept(body) {
+                            if urel_recv_send.try_send(whole).is_err() {
+                                tracing::warn!("urel recv overflow");
+                            }
                         }
                     }
                     // connection opening
@@ -39,8 +83,16 @@ pub async fn multiplex(
                         kind: RelKind::Syn,
                         stream_id,
                         payload,
+                        #[cfg(feature = "trace-ctx")]
+                        trace_ctx,
                         ..
                     } => {
+                        // Link this stream's work to the remote parent span that
+                        // opened it, so client and exit share one trace.
+                        let span = tracing::trace_span!("syn_recv", stream_id);
+                        #[cfg(feature = "trace-ctx")]
+                        link_remote_parent(&span, &trace_ctx);
+                        let _span = span.entered();
                         if conn_tab.get_stream(stream_id).is_some() {
                             tracing::trace!("syn recv {} REACCEPT", stream_id);
                             session.send_bytes(
@@ -49,6 +101,8 @@ pub async fn multiplex(
                                     stream_id,
                                     seqno: 0,
                                     payload: Bytes::new(),
+                                    #[cfg(feature = "trace-ctx")]
+                                    trace_ctx: Bytes::new(),
                                 })
                                 .unwrap()
                                 .into(),
@@ -67,10 +121,19 @@ pub async fn multiplex(
                                 additional_info,
                             );
                             // the RelConn itself is responsible for sending the SynAck. Here we just store the connection into the table, accept it, and be done with it.
-                            conn_tab.set_stream(stream_id, new_conn_back);
+                            // Inbound streams carry no declared priority, so they
+                            // default to the middle class.
+                            conn_tab.set_stream(stream_id, new_conn_back, DEFAULT_PRIORITY);
                             drop(conn_accept_send.send(new_conn).await);
                         }
                     }
+                    // post-FEC residual-loss feedback from the peer
+                    Message::LossReport(report) => {
+                        tracing::trace!("loss report recv {:?}", report);
+                        // Hand it to the session's FEC encoder, which nudges its
+                        // effective target loss (see `FrameEncoder::update_residual`).
+                        session.note_residual_report(report);
+                    }
                     // associated with existing connection
                     Message::Rel {
                         stream_id, kind, ..
@@ -87,6 +150,8 @@ pub async fn multiplex(
                                         stream_id,
                                         seqno: 0,
                                         payload: Bytes::new(),
+                                        #[cfg(feature = "trace-ctx")]
+                                        trace_ctx: Bytes::new(),
                                     })
                                     .unwrap()
                                     .into(),
@@ -100,13 +165,51 @@ pub async fn multiplex(
         };
         // fires on sending messages
         let send_evt = async {
-            let to_send = glob_recv.recv().await?;
-            session.send_bytes(bincode::serialize(&to_send).unwrap().into());
+            // Block for at least one frame, then opportunistically drain whatever
+            // else is already queued so the scheduler sees the full backlog and
+            // can order it by priority rather than strict arrival order.
+            let msg = glob_recv.recv().await?;
+            sched.push(conn_tab.priority(stream_id_of(&msg)), msg);
+            while let Ok(msg) = glob_recv.try_recv() {
+                sched.push(conn_tab.priority(stream_id_of(&msg)), msg);
+            }
+            sched.drain(|msg| {
+                session.send_bytes(bincode::serialize(&msg).unwrap().into());
+            });
+            Ok::<(), anyhow::Error>(())
+        };
+        // fires when the application pushes an unreliable datagram to send
+        let urel_send_evt = async {
+            let datagram = urel_send_recv.recv().await?;
+            // Frame the datagram into MTU-sized, self-describing fragments so a
+            // payload larger than one packet / FEC shard can traverse the
+            // unreliable path; the peer reassembles them in `Reassembler`.
+            #[cfg(feature = "trace-ctx")]
+            let trace_ctx = current_trace_ctx();
+            for frag in fragment(urel_msg_id, &datagram) {
+                drop(
+                    glob_send
+                        .send(Message::Urel {
+                            body: frag,
+                            #[cfg(feature = "trace-ctx")]
+                            trace_ctx: trace_ctx.clone(),
+                        })
+                        .await,
+                );
+            }
+            urel_msg_id = urel_msg_id.wrapping_add(1);
+            Ok::<(), anyhow::Error>(())
+        };
+        // fires when the session's decoder has an aggregated loss report to ship
+        let fec_feedback_evt = async {
+            let report = fec_feedback_recv.recv().await?;
+            tracing::trace!("loss report send {:?}", report);
+            drop(glob_send.send(Message::LossReport(report)).await);
             Ok::<(), anyhow::Error>(())
         };
         // fires on a new stream open request
         let conn_open_evt = async {
-            let (additional_data, result_chan) = conn_open_recv.recv().await?;
+            let (additional_data, priority, result_chan) = conn_open_recv.recv().await?;
             let conn_tab = conn_tab.clone();
             let glob_send = glob_send.clone();
             let dead_send = dead_send.clone();
@@ -133,7 +236,7 @@ pub async fn multiplex(
                             Some(())
                         })
                         .detach();
-                        conn_tab.set_stream(stream_id, conn_back);
+                        conn_tab.set_stream(stream_id, conn_back, priority);
                         stream_id
                     } else {
                         return;
@@ -149,6 +252,11 @@ pub async fn multiplex(
                             payload: Bytes::copy_from_slice(
                                 additional_data.clone().unwrap_or_default().as_bytes(),
                             ),
+                            // Carry this task's span context to the remote end.
+                            // Compiled out entirely unless `trace-ctx` is on, so
+                            // the field never reaches the wire otherwise.
+                            #[cfg(feature = "trace-ctx")]
+                            trace_ctx: current_trace_ctx(),
                         })
                         .await,
                 );
@@ -164,7 +272,259 @@ pub async fn multiplex(
             Ok(())
         };
         // await on them all
-        recv_evt.or(send_evt.or(conn_open_evt.or(dead_evt))).await?;
+        recv_evt
+            .or(send_evt.or(urel_send_evt
+                .or(fec_feedback_evt.or(conn_open_evt.or(dead_evt)))))
+            .await?;
+    }
+}
+
+/// A deficit-round-robin scheduler over a small set of priority classes.
+///
+/// Each class keeps its own FIFO queue and a deficit counter refilled by
+/// `PRIORITY_QUANTUM` bytes every round. `drain` serves the highest non-empty
+/// class while its deficit covers the frame at the head, then moves down,
+/// wrapping around so that lower classes are never completely starved. Because
+/// all frames of a given `stream_id` share one priority they land in the same
+/// queue, so their relative order is always preserved.
+struct PriorityScheduler {
+    queues: Vec<VecDeque<Message>>,
+    deficit: Vec<usize>,
+}
+
+impl PriorityScheduler {
+    fn new() -> Self {
+        PriorityScheduler {
+            queues: (0..PRIORITY_CLASSES).map(|_| VecDeque::new()).collect(),
+            deficit: vec![0; PRIORITY_CLASSES],
+        }
+    }
+
+    /// Maps a raw priority byte onto a class index; higher byte, higher class.
+    fn class_of(priority: u8) -> usize {
+        (priority as usize * PRIORITY_CLASSES / 256).min(PRIORITY_CLASSES - 1)
+    }
+
+    fn push(&mut self, priority: u8, msg: Message) {
+        self.queues[Self::class_of(priority)].push_back(msg);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queues.iter().all(|q| q.is_empty())
+    }
+
+    /// Serves every queued frame through `emit` in deficit-round-robin order.
+    fn drain(&mut self, mut emit: impl FnMut(Message)) {
+        while !self.is_empty() {
+            for class in (0..PRIORITY_CLASSES).rev() {
+                if self.queues[class].is_empty() {
+                    continue;
+                }
+                self.deficit[class] += PRIORITY_QUANTUM;
+                while let Some(front) = self.queues[class].front() {
+                    let cost = frame_cost(front);
+                    if cost > self.deficit[class] {
+                        break;
+                    }
+                    self.deficit[class] -= cost;
+                    emit(self.queues[class].pop_front().unwrap());
+                }
+                // An idle class should not accumulate credit for future bursts.
+                if self.queues[class].is_empty() {
+                    self.deficit[class] = 0;
+                }
+            }
+        }
+    }
+}
+
+/// Serializes the current `tracing` span's context for in-band propagation.
+///
+/// Only compiled in with the `trace-ctx` feature; without it the callers and
+/// the `trace_ctx` wire field vanish too, so non-traced deployments carry no
+/// extra bytes on the hot send path.
+#[cfg(feature = "trace-ctx")]
+fn current_trace_ctx() -> Bytes {
+    use opentelemetry::propagation::{Injector, TextMapPropagator};
+    use opentelemetry::sdk::propagation::TraceContextPropagator;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    struct Carrier(std::collections::HashMap<String, String>);
+    impl Injector for Carrier {
+        fn set(&mut self, key: &str, value: String) {
+            self.0.insert(key.to_owned(), value);
+        }
+    }
+
+    let mut carrier = Carrier(std::collections::HashMap::new());
+    let ctx = tracing::Span::current().context();
+    TraceContextPropagator::new().inject_context(&ctx, &mut carrier);
+    bincode::serialize(&carrier.0)
+        .map(Bytes::from)
+        .unwrap_or_default()
+}
+
+/// Parents `span` to the remote span recorded in `trace_ctx` (when present) so a
+/// trace spans both ends of the session. Only compiled in with `trace-ctx`.
+#[cfg(feature = "trace-ctx")]
+fn link_remote_parent(span: &tracing::Span, trace_ctx: &[u8]) {
+    use opentelemetry::propagation::{Extractor, TextMapPropagator};
+    use opentelemetry::sdk::propagation::TraceContextPropagator;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+    struct Carrier(std::collections::HashMap<String, String>);
+    impl Extractor for Carrier {
+        fn get(&self, key: &str) -> Option<&str> {
+            self.0.get(key).map(|s| s.as_str())
+        }
+        fn keys(&self) -> Vec<&str> {
+            self.0.keys().map(|s| s.as_str()).collect()
+        }
+    }
+
+    if trace_ctx.is_empty() {
+        return;
+    }
+    if let Ok(map) = bincode::deserialize::<std::collections::HashMap<String, String>>(trace_ctx) {
+        let parent = TraceContextPropagator::new().extract(&Carrier(map));
+        span.set_parent(parent);
+    }
+}
+
+/// Approximate on-the-wire cost of a frame, used to charge DRR deficits.
+fn frame_cost(msg: &Message) -> usize {
+    match msg {
+        Message::Urel { body, .. } => body.len() + 8,
+        Message::Rel { payload, .. } => payload.len() + 16,
+        Message::LossReport(_) => 16,
+    }
+}
+
+/// Splits an unreliable payload into self-describing MTU-sized fragments.
+///
+/// Each fragment is prefixed with `(u32 msg_id, u16 frag_idx, u16 frag_count)`
+/// so the receiver can reassemble it without any side channel. `msg_id` must be
+/// unique enough over the reassembly window that distinct datagrams do not
+/// collide; callers typically draw it from a monotonic counter.
+pub fn fragment(msg_id: u32, payload: &[u8]) -> Vec<Bytes> {
+    let frag_count = (payload.len() + FRAG_MTU - 1) / FRAG_MTU;
+    let frag_count = frag_count.max(1);
+    (0..frag_count)
+        .map(|idx| {
+            let start = idx * FRAG_MTU;
+            let chunk = &payload[start..(start + FRAG_MTU).min(payload.len())];
+            let mut out = BytesMut::with_capacity(FRAG_HEADER_LEN + chunk.len());
+            out.extend_from_slice(&msg_id.to_le_bytes());
+            out.extend_from_slice(&(idx as u16).to_le_bytes());
+            out.extend_from_slice(&(frag_count as u16).to_le_bytes());
+            out.extend_from_slice(chunk);
+            out.freeze()
+        })
+        .collect()
+}
+
+/// A partially-received datagram.
+struct Reassembly {
+    parts: Vec<Option<Bytes>>,
+    remaining: usize,
+    deadline: Instant,
+}
+
+/// Reassembles fragmented unreliable datagrams emitted by [`fragment`].
+struct Reassembler {
+    pending: HashMap<u32, Reassembly>,
+    /// Insertion order of `msg_id`s, used to evict the oldest on overflow.
+    order: VecDeque<u32>,
+}
+
+impl Reassembler {
+    fn new() -> Self {
+        Reassembler {
+            pending: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Feeds one received fragment; returns the whole datagram once complete.
+    fn accept(&mut self, frag: Bytes) -> Option<Bytes> {
+        self.evict_expired();
+        if frag.len() < FRAG_HEADER_LEN {
+            tracing::warn!("urel fragment too short ({}B)", frag.len());
+            return None;
+        }
+        let msg_id = u32::from_le_bytes([frag[0], frag[1], frag[2], frag[3]]);
+        let frag_idx = u16::from_le_bytes([frag[4], frag[5]]) as usize;
+        let frag_count = u16::from_le_bytes([frag[6], frag[7]]) as usize;
+        let body = frag.slice(FRAG_HEADER_LEN..);
+        // Fast path: an unfragmented datagram needs no bookkeeping.
+        if frag_count <= 1 {
+            return Some(body);
+        }
+        if frag_idx >= frag_count {
+            tracing::warn!("urel fragment {} out of range {}", frag_idx, frag_count);
+            return None;
+        }
+        // Track the msg_id's arrival order outside the map borrow so this holds
+        // up on editions without disjoint closure captures.
+        if !self.pending.contains_key(&msg_id) {
+            self.pending.insert(
+                msg_id,
+                Reassembly {
+                    parts: vec![None; frag_count],
+                    remaining: frag_count,
+                    deadline: Instant::now() + REASSEMBLY_TIMEOUT,
+                },
+            );
+            self.order.push_back(msg_id);
+        }
+        let entry = self.pending.get_mut(&msg_id).unwrap();
+        // A mismatched count means a stale/colliding msg_id; ignore the fragment.
+        if entry.parts.len() != frag_count {
+            return None;
+        }
+        if entry.parts[frag_idx].is_none() {
+            entry.parts[frag_idx] = Some(body);
+            entry.remaining -= 1;
+        }
+        if entry.remaining == 0 {
+            let entry = self.pending.remove(&msg_id).unwrap();
+            self.order.retain(|id| *id != msg_id);
+            let mut whole = BytesMut::new();
+            for part in entry.parts.into_iter().flatten() {
+                whole.extend_from_slice(&part);
+            }
+            return Some(whole.freeze());
+        }
+        // Enforce the in-flight cap by dropping the oldest partial datagram.
+        while self.pending.len() > MAX_REASSEMBLIES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.pending.remove(&oldest);
+            } else {
+                break;
+            }
+        }
+        None
+    }
+
+    fn evict_expired(&mut self) {
+        let now = Instant::now();
+        let pending = &mut self.pending;
+        self.order.retain(|id| {
+            if pending.get(id).map(|r| r.deadline <= now).unwrap_or(true) {
+                pending.remove(id);
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+/// The stream a frame belongs to, if any; unreliable datagrams have none.
+fn stream_id_of(msg: &Message) -> Option<u16> {
+    match msg {
+        Message::Rel { stream_id, .. } => Some(*stream_id),
+        Message::Urel { .. } | Message::LossReport(_) => None,
     }
 }
 
@@ -172,6 +532,8 @@ pub async fn multiplex(
 struct ConnTable {
     /// Maps IDs to RelConn back handles.
     sid_to_stream: DashMap<u16, RelConnBack>,
+    /// Scheduling priority chosen by whoever opened each stream.
+    sid_to_prio: DashMap<u16, u8>,
 }
 
 impl ConnTable {
@@ -180,12 +542,21 @@ impl ConnTable {
         Some(x.clone())
     }
 
-    fn set_stream(&self, id: u16, handle: RelConnBack) {
+    fn set_stream(&self, id: u16, handle: RelConnBack, priority: u8) {
         self.sid_to_stream.insert(id, handle);
+        self.sid_to_prio.insert(id, priority);
+    }
+
+    /// Priority of a frame, defaulting to [`DEFAULT_PRIORITY`] for unknown or
+    /// streamless frames (e.g. unreliable datagrams).
+    fn priority(&self, sid: Option<u16>) -> u8 {
+        sid.and_then(|sid| self.sid_to_prio.get(&sid).map(|p| *p))
+            .unwrap_or(DEFAULT_PRIORITY)
     }
 
     fn del_stream(&self, id: u16) {
         self.sid_to_stream.remove(&id);
+        self.sid_to_prio.remove(&id);
     }
 
     fn find_id(&self) -> Option<u16> {
@@ -206,3 +577,187 @@ impl ConnTable {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fragment_single_packet_has_header_and_count_one() {
+        let frags = fragment(0x01020304, b"hi");
+        assert_eq!(frags.len(), 1);
+        let f = &frags[0];
+        assert_eq!(&f[0..4], &0x01020304u32.to_le_bytes());
+        assert_eq!(&f[4..6], &0u16.to_le_bytes()); // frag_idx
+        assert_eq!(&f[6..8], &1u16.to_le_bytes()); // frag_count
+        assert_eq!(&f[FRAG_HEADER_LEN..], b"hi");
+    }
+
+    #[test]
+    fn fragment_splits_oversized_payload_by_mtu() {
+        let payload = vec![7u8; FRAG_MTU * 2 + 10];
+        let frags = fragment(1, &payload);
+        assert_eq!(frags.len(), 3);
+        for (idx, f) in frags.iter().enumerate() {
+            assert_eq!(&f[4..6], &(idx as u16).to_le_bytes());
+            assert_eq!(&f[6..8], &3u16.to_le_bytes());
+        }
+    }
+
+    #[test]
+    fn reassembler_fast_path_for_single_fragment() {
+        let frags = fragment(42, b"hello world");
+        let mut reasm = Reassembler::new();
+        assert_eq!(&reasm.accept(frags[0].clone()).unwrap()[..], b"hello world");
+        assert!(reasm.pending.is_empty());
+    }
+
+    #[test]
+    fn reassembler_completes_out_of_order() {
+        let payload = vec![0xabu8; FRAG_MTU * 2 + 10];
+        let frags = fragment(7, &payload);
+        let mut reasm = Reassembler::new();
+        assert!(reasm.accept(frags[2].clone()).is_none());
+        assert!(reasm.accept(frags[0].clone()).is_none());
+        let whole = reasm.accept(frags[1].clone()).unwrap();
+        assert_eq!(&whole[..], &payload[..]);
+        assert!(reasm.pending.is_empty());
+    }
+
+    #[test]
+    fn reassembler_ignores_duplicate_fragments() {
+        let payload = vec![9u8; FRAG_MTU + 1];
+        let frags = fragment(3, &payload);
+        assert_eq!(frags.len(), 2);
+        let mut reasm = Reassembler::new();
+        assert!(reasm.accept(frags[0].clone()).is_none());
+        // A duplicate must not drop `remaining` twice / complete early.
+        assert!(reasm.accept(frags[0].clone()).is_none());
+        let whole = reasm.accept(frags[1].clone()).unwrap();
+        assert_eq!(&whole[..], &payload[..]);
+    }
+
+    #[test]
+    fn reassembler_rejects_short_and_out_of_range_fragments() {
+        let mut reasm = Reassembler::new();
+        assert!(reasm.accept(Bytes::from_static(b"abc")).is_none());
+        // frag_idx (3) >= frag_count (2): bogus, must be dropped untracked.
+        let mut bogus = BytesMut::new();
+        bogus.extend_from_slice(&5u32.to_le_bytes());
+        bogus.extend_from_slice(&3u16.to_le_bytes());
+        bogus.extend_from_slice(&2u16.to_le_bytes());
+        bogus.extend_from_slice(b"x");
+        assert!(reasm.accept(bogus.freeze()).is_none());
+        assert!(reasm.pending.is_empty());
+    }
+
+    #[test]
+    fn reassembler_caps_in_flight_datagrams() {
+        let mut reasm = Reassembler::new();
+        for id in 0..(MAX_REASSEMBLIES as u32 + 50) {
+            let frags = fragment(id, &vec![0u8; FRAG_MTU + 1]);
+            assert!(reasm.accept(frags[0].clone()).is_none());
+        }
+        assert!(reasm.pending.len() <= MAX_REASSEMBLIES);
+        assert_eq!(reasm.pending.len(), reasm.order.len());
+    }
+
+    #[test]
+    fn reassembler_evicts_expired_partials() {
+        let frags = fragment(1, &vec![0u8; FRAG_MTU + 1]);
+        let mut reasm = Reassembler::new();
+        assert!(reasm.accept(frags[0].clone()).is_none());
+        assert!(reasm.pending.contains_key(&1));
+        // Force the partial past its deadline; the next accept should sweep it.
+        reasm.pending.get_mut(&1).unwrap().deadline = Instant::now() - Duration::from_secs(1);
+        let _ = reasm.accept(fragment(2, b"other")[0].clone());
+        assert!(!reasm.pending.contains_key(&1));
+    }
+
+    fn rel(stream_id: u16, payload: &[u8]) -> Message {
+        Message::Rel {
+            kind: RelKind::Data,
+            stream_id,
+            seqno: 0,
+            payload: Bytes::copy_from_slice(payload),
+        }
+    }
+
+    #[test]
+    fn class_of_maps_priority_to_band() {
+        assert_eq!(PriorityScheduler::class_of(0), 0);
+        assert_eq!(PriorityScheduler::class_of(255), PRIORITY_CLASSES - 1);
+        assert!(PriorityScheduler::class_of(DEFAULT_PRIORITY) > 0);
+    }
+
+    #[test]
+    fn drain_serves_high_priority_before_low() {
+        let mut sched = PriorityScheduler::new();
+        for i in 0..3 {
+            sched.push(0, rel(i, b"bulk"));
+        }
+        for i in 100..102 {
+            sched.push(255, rel(i, b"live"));
+        }
+        let mut order = vec![];
+        sched.drain(|m| {
+            if let Message::Rel { stream_id, .. } = m {
+                order.push(stream_id);
+            }
+        });
+        // Everything is served (nothing starved), high class first, and the two
+        // high-class frames keep their arrival order.
+        assert_eq!(order.len(), 5);
+        let first_live = order.iter().position(|s| *s >= 100).unwrap();
+        let first_bulk = order.iter().position(|s| *s < 100).unwrap();
+        assert!(first_live < first_bulk);
+        assert_eq!(order[0], 100);
+        assert_eq!(order[1], 101);
+        assert!(sched.is_empty());
+    }
+
+    #[test]
+    fn drain_preserves_fifo_within_a_stream() {
+        let mut sched = PriorityScheduler::new();
+        for seqno in 0..5 {
+            sched.push(
+                DEFAULT_PRIORITY,
+                Message::Rel {
+                    kind: RelKind::Data,
+                    stream_id: 7,
+                    seqno,
+                    payload: Bytes::new(),
+                },
+            );
+        }
+        let mut seqs = vec![];
+        sched.drain(|m| {
+            if let Message::Rel { seqno, .. } = m {
+                seqs.push(seqno);
+            }
+        });
+        assert_eq!(seqs, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn drain_does_not_starve_low_priority_under_bulk() {
+        let mut sched = PriorityScheduler::new();
+        let big = vec![0u8; PRIORITY_QUANTUM * 4];
+        for i in 0..8 {
+            sched.push(255, rel(i, &big));
+        }
+        sched.push(0, rel(900, b"x"));
+        let mut saw_low = false;
+        let mut emitted = 0;
+        sched.drain(|m| {
+            emitted += 1;
+            if let Message::Rel { stream_id, .. } = m {
+                if stream_id == 900 {
+                    saw_low = true;
+                }
+            }
+        });
+        assert!(saw_low);
+        assert_eq!(emitted, 9);
+    }
+}
@@ -0,0 +1,49 @@
+use crate::fec::ResidualReport;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+/// Sequence number of a reliable-stream frame.
+pub type Seqno = u64;
+
+/// A single frame exchanged over a multiplexed session.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Message {
+    /// A reliable-stream control or data frame.
+    Rel {
+        kind: RelKind,
+        stream_id: u16,
+        seqno: Seqno,
+        payload: Bytes,
+        /// Serialized parent span context for distributed tracing. `multiplex`
+        /// fills it on the `Syn` frames it sends and leaves it empty on its
+        /// `SynAck` and `Rst` replies. Only present when the `trace-ctx` feature is
+        /// enabled: leaving it out otherwise means non-traced deployments pay
+        /// nothing on the wire. Enabling it is therefore a wire-format change,
+        /// so both ends of a session must be built with the same setting.
+        #[cfg(feature = "trace-ctx")]
+        trace_ctx: Bytes,
+    },
+    /// An unreliable datagram fragment (see the mux fragmentation layer).
+    Urel {
+        body: Bytes,
+        /// Serialized parent span context, as for [`Message::Rel`]; gated on the
+        /// `trace-ctx` feature for the same wire-cost reason.
+        #[cfg(feature = "trace-ctx")]
+        trace_ctx: Bytes,
+    },
+    /// Aggregated post-FEC residual-loss feedback for the peer's encoder.
+    LossReport(ResidualReport),
+}
+
+/// The kind of a reliable-stream frame.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RelKind {
+    Syn,
+    SynAck,
+    Data,
+    DataAck,
+    Fin,
+    FinAck,
+    Rst,
+}